@@ -1,12 +1,38 @@
-use std::collections::{VecMap, HashMap};
+use std::collections::{HashMap, HashSet, BTreeMap, VecDeque};
 
 #[derive(RustcEncodable, RustcDecodable, Clone, Copy)]
 #[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Debug)]
 pub struct MsgId(pub u64);
 
+/// `PieceNum(shard_index, total_shards, k, orig_len)`
+///
+/// `shard_index` is the 1-based position of this shard among the
+/// `total_shards` pieces that make up the message. Of those, the first `k`
+/// are the original data shards and the remaining `total_shards - k` are
+/// Reed-Solomon parity shards computed over GF(2^8). `orig_len` is the
+/// length in bytes of the original, unpadded message, and is used to trim
+/// the padding added to the final data shard once the message has been
+/// reassembled.
+///
+/// When `k == 1` the message fit in a single chunk and coding is bypassed
+/// entirely: `total_shards == k == 1` and the chunk carries the message
+/// verbatim.
+///
+/// `k == 1` combined with `total_shards != 1` instead marks a shard of a
+/// *streamed* message (see `Sender::enqueue_stream`): `shard_index` is the
+/// piece's 1-based sequence number and the total piece count isn't known
+/// up front, so `total_shards` carries the sentinel `STREAM_CONTINUATION`
+/// until the terminal piece arrives, at which point it carries the real,
+/// now-known count. `orig_len` is unused by streamed pieces, each of which
+/// already carries its exact, unpadded bytes.
 #[derive(RustcEncodable, RustcDecodable, Clone, Copy)]
 #[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Debug)]
-pub struct PieceNum(pub u16, pub u16);
+pub struct PieceNum(pub u16, pub u16, pub u16, pub u32);
+
+/// Sentinel `total_shards` value used by a streamed message's shards while
+/// the total piece count is still unknown. No ordinary (non-streamed)
+/// message ever has a `total_shards` of zero.
+pub const STREAM_CONTINUATION: u16 = 0;
 
 #[derive(RustcEncodable, RustcDecodable, Clone)]
 #[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Debug)]
@@ -18,25 +44,71 @@ pub struct CompleteMessage(pub MsgId, pub Vec<u8>);
 
 struct MsgStage {
     this_id: MsgId,
-    total_pieces: u16,
+    total_shards: u16,
+    k: u16,
+    orig_len: u32,
     pieces: HashMap<usize, MsgChunk>,
-    size: usize
+    size: usize,
+    // Streaming support: how much of the contiguous prefix starting at
+    // sequence 1 has already been handed back by
+    // `MsgQueue::insert_stream_chunk`. Unused by coded/bypassed stages.
+    emitted_through: usize
+}
+
+/// How a `MsgQueue` decides which completed messages to hand back, and in
+/// what order.
+#[derive(Clone, Copy, Debug)]
+pub enum DeliveryMode {
+    /// "Newest state wins": completing a message drops every other
+    /// in-flight message with an earlier id, and late arrivals below the
+    /// last released id are ignored. Good for a latest-state stream where
+    /// only the freshest update matters.
+    Monotonic,
+    /// Buffers messages that complete ahead of a gap and releases them in
+    /// id order as the gap fills in. Ids below the low-water mark (the next
+    /// id due for release) are dropped as duplicates. At most `window`
+    /// completed messages are held behind a gap: once one more completes,
+    /// the gap is skipped and release resumes from the lowest buffered id.
+    Ordered { window: usize },
+    /// Releases a message as soon as its pieces are complete, in whatever
+    /// order that happens, only suppressing exact duplicates. No in-flight
+    /// message is ever discarded because a later one completed first.
+    /// Remembers the last `window` released ids to recognize duplicates;
+    /// older ones are forgotten, so a very late duplicate of a message
+    /// released more than `window` releases ago may be delivered again.
+    Unordered { window: usize }
 }
 
 pub struct MsgQueue {
     last_released: Option<MsgId>,
     stages: HashMap<MsgId, MsgStage>,
     max_size: Option<usize>,
-    cur_size: usize
+    cur_size: usize,
+    mode: DeliveryMode,
+    // `Ordered` mode: messages that completed ahead of `next_release`,
+    // waiting for the gap before them to fill.
+    reorder_buffer: BTreeMap<u64, CompleteMessage>,
+    next_release: u64,
+    // `Unordered` mode: ids already handed back, so a duplicate chunk
+    // doesn't release the same message twice. `released_order` is the same
+    // ids in release order, used to evict the oldest once there are more
+    // than `window` of them so this doesn't grow without bound.
+    released_ids: HashSet<u64>,
+    released_order: VecDeque<u64>
 }
 
 impl MsgQueue {
-    pub fn new(max_size: Option<usize>) -> MsgQueue {
+    pub fn new(max_size: Option<usize>, mode: DeliveryMode) -> MsgQueue {
         MsgQueue {
             last_released: None,
             stages: HashMap::new(),
             max_size: max_size,
             cur_size: 0,
+            mode: mode,
+            reorder_buffer: BTreeMap::new(),
+            next_release: 0,
+            released_ids: HashSet::new(),
+            released_order: VecDeque::new()
         }
     }
 
@@ -54,6 +126,20 @@ impl MsgQueue {
         }
     }
 
+    // `Unordered` mode bookkeeping: remembers `id` as released, evicting
+    // the oldest remembered id once there are more than `window` of them
+    // so `released_ids` doesn't grow without bound over a long-running
+    // connection.
+    fn mark_released(&mut self, id: u64, window: usize) {
+        self.released_ids.insert(id);
+        self.released_order.push_back(id);
+        while self.released_order.len() > window {
+            if let Some(old) = self.released_order.pop_front() {
+                self.released_ids.remove(&old);
+            }
+        }
+    }
+
     // If we are over capacity, this function will remove messages from
     // the beginning of the queue until we are no longer above capacity.
     fn prune(&mut self) {
@@ -73,21 +159,67 @@ impl MsgQueue {
         }
     }
 
-    pub fn insert_chunk(&mut self, chunk: MsgChunk) -> Option<CompleteMessage> {
+    /// Inserts a newly-arrived chunk, returning every message that becomes
+    /// releasable as a result, in the order they should be handed to the
+    /// application. This is usually zero or one message, but `Ordered`
+    /// mode can release several at once when a chunk fills a gap that
+    /// unblocks a run of already-completed messages.
+    pub fn insert_chunk(&mut self, chunk: MsgChunk) -> Vec<CompleteMessage> {
         let id = chunk.0;
         self.prune();
 
-        // If the last published message was released before this chunk,
-        // don't do anything and ignore it.
-        if let Some(last) = self.last_released {
-            if last.0 >= id.0 {
-                return None;
+        match self.mode {
+            DeliveryMode::Monotonic => {
+                // If the last published message was released after this
+                // chunk's id, don't do anything and ignore it.
+                if let Some(last) = self.last_released {
+                    if last.0 >= id.0 {
+                        return Vec::new();
+                    }
+                }
+                match self.complete_stage(chunk) {
+                    Some(msg) => { self.mark_published(id); vec![msg] }
+                    None => Vec::new(),
+                }
+            }
+
+            DeliveryMode::Unordered { window } => {
+                if self.released_ids.contains(&id.0) {
+                    return Vec::new();
+                }
+                match self.complete_stage(chunk) {
+                    Some(msg) => { self.mark_released(id.0, window); vec![msg] }
+                    None => Vec::new(),
+                }
+            }
+
+            DeliveryMode::Ordered { window } => {
+                if id.0 < self.next_release {
+                    return Vec::new();
+                }
+                match self.complete_stage(chunk) {
+                    Some(msg) => self.release_ordered(id.0, msg, window),
+                    None => Vec::new(),
+                }
             }
         }
+    }
 
-        // If the chunk has only one piece to it, publish it immediately.
-        if (chunk.1).1 == 1 {
-            self.mark_published(id);
+    // Feeds `chunk` into its stage (or bypasses straight to completion for
+    // single-shard messages), returning the reassembled message once enough
+    // shards have arrived. Doesn't touch any delivery-mode bookkeeping.
+    //
+    // `None` also covers the case where the stage became ready but its
+    // shard set turned out to be undecodable (see `MsgStage::merge`); such
+    // a message is unrecoverable and is dropped, same as any other stage
+    // that can never complete.
+    fn complete_stage(&mut self, chunk: MsgChunk) -> Option<CompleteMessage> {
+        let id = chunk.0;
+
+        // If the chunk needed no coding (it is the only data shard and
+        // there are no parity shards), it's already complete.
+        let PieceNum(_, total_shards, k, _) = chunk.1;
+        if k == 1 && total_shards == 1 {
             return Some(CompleteMessage(id, chunk.2));
         }
 
@@ -103,43 +235,214 @@ impl MsgQueue {
             if ready {
                 let mut stage = self.stages.remove(&id).unwrap();
                 self.cur_size -= stage.size;
-                self.mark_published(id);
-                return Some(stage.merge());
+                stage.merge()
             } else {
-                return None;
+                None
             }
         // We got a new chunk that needs to be processed.
         } else {
             self.cur_size += chunk.2.len();
             self.stages.insert(id, MsgStage::new(chunk));
-            return None;
+            None
+        }
+    }
+
+    // `Ordered` mode bookkeeping: buffer `msg` until every id below it has
+    // been released, then drain as long a contiguous run as has arrived.
+    fn release_ordered(&mut self, completed_id: u64, msg: CompleteMessage, window: usize) -> Vec<CompleteMessage> {
+        self.reorder_buffer.insert(completed_id, msg);
+
+        // The buffer has grown past the window: the oldest gap is never
+        // going to fill, so jump `next_release` forward to the lowest id
+        // we're actually holding.
+        if self.reorder_buffer.len() > window {
+            if let Some(&lowest) = self.reorder_buffer.keys().next() {
+                if lowest > self.next_release {
+                    self.next_release = lowest;
+                }
+            }
+        }
+
+        let mut released = Vec::new();
+        while let Some(msg) = self.reorder_buffer.remove(&self.next_release) {
+            released.push(msg);
+            self.next_release += 1;
         }
+
+        // Anything still being reassembled below the new low-water mark
+        // can never be released in order; it's unrecoverable, so drop it.
+        // Streaming stages (see `PieceNum`) are exempt: `insert_stream_chunk`
+        // bypasses `self.mode` entirely and delivers them incrementally as
+        // pieces arrive, so an in-progress stream has nothing to do with
+        // `Ordered` mode's reorder buffer and shouldn't be collected by it.
+        let stale: Vec<MsgId> = self.stages.iter()
+            .filter(|&(id, stage)| id.0 < self.next_release && !stage.is_streaming())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale {
+            if let Some(stage) = self.stages.remove(&id) {
+                self.cur_size -= stage.size;
+            }
+        }
+
+        released
     }
 
+    /// Returns a `(MsgId, bitmap)` pair for every coded message that is
+    /// still being reassembled, where `bitmap` has one bit per shard (LSB
+    /// first, one shard per bit starting at shard 1) set if that shard has
+    /// not yet arrived. Intended to be turned into `WireMsg::Nack` packets
+    /// and sent back to the message's sender so it can retransmit exactly
+    /// the missing shards.
+    ///
+    /// Streamed messages (see `PieceNum`) are excluded: their pieces
+    /// arrive strictly in order with no fixed shard count known up front,
+    /// so there's no useful bitmap to report, only "everything past the
+    /// frontier", which a NACK can't express.
+    pub fn missing_shards(&self) -> Vec<(MsgId, Vec<u8>)> {
+        self.stages.values()
+            .filter(|stage| !stage.is_streaming() && !stage.is_ready())
+            .map(|stage| (stage.this_id, stage.missing_bitmap()))
+            .collect()
+    }
+
+    /// Feeds a single shard of a streamed message (see `PieceNum`), and
+    /// returns the *delta* — only the bytes newly made available by this
+    /// chunk — if it extended the contiguous run of pieces received so
+    /// far; an empty `Vec` if it didn't (e.g. it arrived ahead of a gap). Unlike a
+    /// coded message's `merge`, a delivered piece is immediately dropped
+    /// from the stage rather than kept around, so a long stream never
+    /// has more than the not-yet-contiguous tail resident at once.
+    /// Bypasses `self.mode` entirely: a stream's pieces are already
+    /// strictly ordered by construction, so there's no reordering left to
+    /// do, only incremental delivery of however much has arrived
+    /// contiguously.
+    ///
+    /// Once the terminal piece's delta has been returned, the stream's
+    /// stage is dropped, same as a fully reassembled ordinary message.
+    pub fn insert_stream_chunk(&mut self, chunk: MsgChunk) -> Vec<CompleteMessage> {
+        let id = chunk.0;
+        self.prune();
+
+        if self.stages.contains_key(&id) {
+            let stage = self.stages.get_mut(&id).unwrap();
+            self.cur_size += stage.add_chunk(chunk);
+        } else {
+            self.cur_size += chunk.2.len();
+            self.stages.insert(id, MsgStage::new(chunk));
+        }
+
+        let stage = self.stages.get_mut(&id).unwrap();
+        let frontier = stage.stream_frontier();
+        if frontier <= stage.emitted_through {
+            return Vec::new();
+        }
+        let (delta, freed) = stage.drain_stream_delta(frontier);
+        stage.emitted_through = frontier;
+        self.cur_size -= freed;
+
+        // Known total reached by the contiguous run: the terminal piece
+        // has arrived and everything up to it has too.
+        let done = stage.total_shards != STREAM_CONTINUATION
+            && frontier >= stage.total_shards as usize;
 
+        if done {
+            if let Some(stage) = self.stages.remove(&id) {
+                self.cur_size -= stage.size;
+            }
+        }
+
+        vec![CompleteMessage(id, delta)]
+    }
 }
 
 impl MsgStage {
     fn new(starter: MsgChunk) -> MsgStage {
-        let PieceNum(_, out_of) = starter.1;
+        let PieceNum(_, total_shards, k, orig_len) = starter.1;
 
         let mut stage = MsgStage {
             this_id: starter.0,
-            total_pieces: out_of,
-            pieces: HashMap::with_capacity(out_of as usize),
-            size: 0
+            total_shards: total_shards,
+            k: k,
+            orig_len: orig_len,
+            pieces: HashMap::with_capacity(total_shards as usize),
+            size: 0,
+            emitted_through: 0
         };
 
         stage.add_chunk(starter);
         stage
     }
 
+    // Ready as soon as any `k` of the `k + m` shards have arrived: that is
+    // enough to invert the coding matrix and recover every data shard.
+    //
+    // A `k <= 1` stage with `total_shards != 1` is a streamed message
+    // (see `PieceNum`) rather than a coded one: it's ready once the
+    // terminal piece has told us the real piece count and every piece up
+    // to it has arrived.
     fn is_ready(&self) -> bool {
-        self.total_pieces as usize == self.pieces.len()
+        if self.is_streaming() {
+            self.total_shards != STREAM_CONTINUATION && self.pieces.len() >= self.total_shards as usize
+        } else {
+            self.pieces.len() >= self.k as usize
+        }
+    }
+
+    // Whether this stage belongs to a streamed message (see `PieceNum`)
+    // rather than a coded one.
+    fn is_streaming(&self) -> bool {
+        self.k <= 1 && self.total_shards != 1
+    }
+
+    // The sequence number of the last piece in the contiguous run of stream
+    // pieces present starting just after `emitted_through`, e.g. if `emitted_through` is 2 and
+    // pieces 3 and 4 have arrived but 5 hasn't, this returns 4 (regardless
+    // of what's arrived beyond the gap). Pieces at or below
+    // `emitted_through` have already been drained, so scanning resumes
+    // from there instead of sequence 1.
+    fn stream_frontier(&self) -> usize {
+        let mut n = self.emitted_through;
+        while self.pieces.contains_key(&(n + 1)) { n += 1; }
+        n
+    }
+
+    // Concatenates and removes pieces `emitted_through + 1 ..= through` in
+    // sequence order, returning the delta bytes along with the number of
+    // bytes freed from `self.pieces` so the caller can keep `cur_size` in
+    // sync. Draining as we go means a stream never buffers more than its
+    // not-yet-contiguous tail.
+    fn drain_stream_delta(&mut self, through: usize) -> (Vec<u8>, usize) {
+        let mut data = Vec::new();
+        let mut freed = 0;
+        for i in self.emitted_through + 1 .. through + 1 {
+            let chunk = self.pieces.remove(&i).unwrap();
+            freed += chunk.2.len();
+            data.extend_from_slice(&chunk.2);
+        }
+        self.size -= freed;
+        (data, freed)
+    }
+
+    fn missing_bitmap(&self) -> Vec<u8> {
+        let total = self.total_shards as usize;
+        let mut bitmap = vec![0u8; (total + 7) / 8];
+        for i in 0 .. total {
+            if !self.pieces.contains_key(&(i + 1)) {
+                bitmap[i / 8] |= 1 << (i % 8);
+            }
+        }
+        bitmap
     }
 
     fn add_chunk(&mut self, chunk: MsgChunk) -> usize {
-        let PieceNum(this, _) = chunk.1;
+        let PieceNum(this, total_shards, _, _) = chunk.1;
+        // A streamed message's terminal piece is the first to carry the
+        // real piece count; remember it so `is_ready` knows what it's
+        // waiting for.
+        if self.k <= 1 && total_shards != STREAM_CONTINUATION {
+            self.total_shards = total_shards;
+        }
         if !self.pieces.contains_key(&(this as usize)) {
             let size = chunk.2.len();
             self.size += size;
@@ -148,132 +451,529 @@ impl MsgStage {
         } else { 0 }
     }
 
-    fn merge(mut self) -> CompleteMessage {
-        let mut size = 0;
+    // Reassembles the message from its received shards. Returns `None` if
+    // the shard set turns out not to decode, which should only happen if a
+    // sender ever produced more than 256 total shards for one message: the
+    // Vandermonde rows `rs::systematic_matrix` builds its parity from are
+    // indexed by `i as u8`, so rows 256 and up alias earlier rows mod 256,
+    // and an unlucky `k`-subset of aliased rows is singular. `Sender::enqueue`
+    // refuses to produce such a message, so this is a defensive fallback,
+    // not the primary guard.
+    fn merge(self) -> Option<CompleteMessage> {
+        let k = self.k as usize;
+
+        if k <= 1 {
+            // A bypassed message is a single verbatim shard; nothing to
+            // decode.
+            if self.total_shards == 1 {
+                let chunk = self.pieces.into_iter().next().unwrap().1;
+                let MsgChunk(_, _, mut bytes) = chunk;
+                bytes.truncate(self.orig_len as usize);
+                return Some(CompleteMessage(self.this_id, bytes));
+            }
 
-        for (_, &MsgChunk(_, _, ref bytes)) in self.pieces.iter() {
-            size += bytes.len();
+            // A streamed message: concatenate its pieces in sequence
+            // order. Each already carries its exact bytes, so there's no
+            // padding left to trim.
+            let mut order: Vec<usize> = self.pieces.keys().cloned().collect();
+            order.sort();
+            let mut data = Vec::new();
+            for key in order {
+                data.extend_from_slice(&self.pieces[&key].2);
+            }
+            return Some(CompleteMessage(self.this_id, data));
         }
 
-        let mut v = Vec::with_capacity(size);
+        let mut have: Vec<usize> = self.pieces.keys().cloned().collect();
+        have.sort();
+        have.truncate(k);
+
+        let shard_len = self.pieces[&have[0]].2.len();
+        let coding = rs::systematic_matrix(self.total_shards as usize, k);
+        let rows: Vec<Vec<u8>> = have.iter().map(|&r| coding[r - 1].clone()).collect();
+        let inv = match rs::invert_matrix(&rows) {
+            Some(inv) => inv,
+            None => return None,
+        };
 
-        for (_, &mut MsgChunk(_, _, ref mut bytes)) in self.pieces.iter_mut() {
-            for &byte in bytes.iter() {
-                v.push(byte);
+        let received: Vec<&[u8]> = have.iter().map(|&r| &self.pieces[&r].2[..]).collect();
+
+        let mut data = Vec::with_capacity(k * shard_len);
+        for shard in 0 .. k {
+            for byte_idx in 0 .. shard_len {
+                let mut acc = 0u8;
+                for col in 0 .. k {
+                    acc ^= rs::gf_mul(inv[shard][col], received[col][byte_idx]);
+                }
+                data.push(acc);
             }
         }
 
-        CompleteMessage(self.this_id, v)
+        data.truncate(self.orig_len as usize);
+        Some(CompleteMessage(self.this_id, data))
     }
 }
 
+/// Reed-Solomon coding over GF(2^8), used to turn the `k` data shards of a
+/// message into `k + m` shards of which any `k` are sufficient to recover
+/// the original data.
+pub mod rs {
+    // Reduction polynomial x^8 + x^4 + x^3 + x^2 + 1 (0x11d), the one used
+    // by most practical RS implementations.
+    const REDUCTION: u8 = 0x1d;
+
+    /// Multiplies two elements of GF(2^8).
+    pub fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+        let mut product: u8 = 0;
+        for _ in 0 .. 8 {
+            if b & 1 != 0 { product ^= a; }
+            let carry = a & 0x80;
+            a <<= 1;
+            if carry != 0 { a ^= REDUCTION; }
+            b >>= 1;
+        }
+        product
+    }
+
+    /// Raises `a` to the `e`th power in GF(2^8). Follows the convention
+    /// `0^0 == 1`.
+    fn gf_pow(a: u8, e: u8) -> u8 {
+        if e == 0 { return 1; }
+        if a == 0 { return 0; }
+
+        let mut result = 1u8;
+        let mut base = a;
+        let mut exp = e;
+        while exp > 0 {
+            if exp & 1 != 0 { result = gf_mul(result, base); }
+            base = gf_mul(base, base);
+            exp >>= 1;
+        }
+        result
+    }
+
+    /// The multiplicative inverse of `a` in GF(2^8). `a` must be non-zero.
+    fn gf_inv(a: u8) -> u8 {
+        gf_pow(a, 254)
+    }
+
+    /// Builds a `rows x cols` Vandermonde matrix over GF(2^8), `m[i][j] = i^j`.
+    fn vandermonde(rows: usize, cols: usize) -> Vec<Vec<u8>> {
+        (0 .. rows).map(|i| {
+            (0 .. cols).map(|j| gf_pow(i as u8, j as u8)).collect()
+        }).collect()
+    }
+
+    /// Inverts a square matrix over GF(2^8) via Gauss-Jordan elimination
+    /// with partial pivoting. Returns `None` if the matrix is singular.
+    pub fn invert_matrix(matrix: &[Vec<u8>]) -> Option<Vec<Vec<u8>>> {
+        let n = matrix.len();
+        let mut aug: Vec<Vec<u8>> = matrix.iter().enumerate().map(|(i, row)| {
+            let mut r = row.clone();
+            for j in 0 .. n { r.push(if i == j { 1 } else { 0 }); }
+            r
+        }).collect();
+
+        for col in 0 .. n {
+            let pivot = match (col .. n).find(|&r| aug[r][col] != 0) {
+                Some(p) => p,
+                None => return None,
+            };
+            aug.swap(col, pivot);
+
+            let inv = gf_inv(aug[col][col]);
+            for v in aug[col].iter_mut() { *v = gf_mul(*v, inv); }
+
+            for row in 0 .. n {
+                if row == col { continue; }
+                let factor = aug[row][col];
+                if factor == 0 { continue; }
+                for c in 0 .. aug[row].len() {
+                    let scaled = gf_mul(factor, aug[col][c]);
+                    aug[row][c] ^= scaled;
+                }
+            }
+        }
+
+        Some(aug.into_iter().map(|row| row[n ..].to_vec()).collect())
+    }
+
+    /// Builds the `total x k` systematic coding matrix used to turn `k`
+    /// data shards into `total` shards: the first `k` rows are the
+    /// identity (so data shards pass through unmodified) and the
+    /// remaining `total - k` rows are parity rows, derived from a
+    /// Vandermonde matrix so that any `k` of the `total` rows are
+    /// guaranteed to be linearly independent.
+    pub fn systematic_matrix(total: usize, k: usize) -> Vec<Vec<u8>> {
+        let vander = vandermonde(total, k);
+        let top: Vec<Vec<u8>> = vander[0 .. k].to_vec();
+        let top_inv = invert_matrix(&top)
+            .expect("top k rows of a Vandermonde matrix are always invertible");
+
+        vander.iter().map(|row| {
+            (0 .. k).map(|col| {
+                let mut acc = 0u8;
+                for i in 0 .. k {
+                    acc ^= gf_mul(row[i], top_inv[i][col]);
+                }
+                acc
+            }).collect()
+        }).collect()
+    }
+
+    /// Encodes a single parity shard (`parity_row`, 0-based among the `m`
+    /// parity rows) from the `k` data shards, which must all be the same
+    /// length.
+    pub fn encode_parity_shard(data_shards: &[&[u8]], total: usize, parity_row: usize) -> Vec<u8> {
+        let k = data_shards.len();
+        let coding = systematic_matrix(total, k);
+        let row = &coding[k + parity_row];
+        let shard_len = data_shards[0].len();
+
+        (0 .. shard_len).map(|byte_idx| {
+            let mut acc = 0u8;
+            for i in 0 .. k {
+                acc ^= gf_mul(row[i], data_shards[i][byte_idx]);
+            }
+            acc
+        }).collect()
+    }
+}
+
+
+// Coding tests
+
+#[test] fn gf_mul_identity() {
+    assert_eq!(rs::gf_mul(1, 200), 200);
+    assert_eq!(rs::gf_mul(200, 0), 0);
+}
+
+#[test] fn systematic_matrix_is_identity_on_data_rows() {
+    let m = rs::systematic_matrix(5, 3);
+    assert_eq!(m[0], vec![1, 0, 0]);
+    assert_eq!(m[1], vec![0, 1, 0]);
+    assert_eq!(m[2], vec![0, 0, 1]);
+}
+
+#[test] fn any_k_rows_invert() {
+    let m = rs::systematic_matrix(5, 3);
+    let rows = vec![m[1].clone(), m[3].clone(), m[4].clone()];
+    assert!(rs::invert_matrix(&rows).is_some());
+}
 
 // Stage tests
 
 #[test] fn is_ready_single_complete() {
-    let comp_chunk = MsgChunk(MsgId(0), PieceNum(1, 1), vec![0]);
+    let comp_chunk = MsgChunk(MsgId(0), PieceNum(1, 1, 1, 1), vec![0]);
     let stage = MsgStage::new(comp_chunk);
     assert!(stage.is_ready());
-    assert!(stage.merge() == CompleteMessage(MsgId(0), vec![0]));
+    assert!(stage.merge() == Some(CompleteMessage(MsgId(0), vec![0])));
 }
 
 #[test] fn is_ready_single_incomplete() {
-    let incomp_chunk = MsgChunk(MsgId(0), PieceNum(1, 2), vec![0]);
+    let incomp_chunk = MsgChunk(MsgId(0), PieceNum(1, 2, 2, 2), vec![0]);
     let stage = MsgStage::new(incomp_chunk);
     assert!(!stage.is_ready());
 }
 
 #[test] fn is_ready_double_complete() {
-    let c1 = MsgChunk(MsgId(0), PieceNum(1, 2), vec![0]);
-    let c2 = MsgChunk(MsgId(0), PieceNum(2, 2), vec![1]);
+    let c1 = MsgChunk(MsgId(0), PieceNum(1, 2, 2, 2), vec![0]);
+    let c2 = MsgChunk(MsgId(0), PieceNum(2, 2, 2, 2), vec![1]);
 
     let mut stage = MsgStage::new(c1.clone());
     stage.add_chunk(c2.clone());
     assert!(stage.is_ready());
-    assert!(stage.merge() == CompleteMessage(MsgId(0), vec![0, 1]));
+    assert!(stage.merge() == Some(CompleteMessage(MsgId(0), vec![0, 1])));
 
     // Now in the opposite order
 
     let mut stage = MsgStage::new(c2.clone());
     stage.add_chunk(c1.clone());
     assert!(stage.is_ready());
-    assert!(stage.merge() == CompleteMessage(MsgId(0), vec![0, 1]));
+    assert!(stage.merge() == Some(CompleteMessage(MsgId(0), vec![0, 1])));
 }
 
 #[test] fn is_ready_double_same() {
-    let c1 = MsgChunk(MsgId(0), PieceNum(1, 2), vec![0]);
+    let c1 = MsgChunk(MsgId(0), PieceNum(1, 2, 2, 2), vec![0]);
 
     let mut stage = MsgStage::new(c1.clone());
     stage.add_chunk(c1);
     assert!(!stage.is_ready());
 }
 
+#[test] fn reconstructs_from_parity_shard_alone() {
+    // k=2 data shards, m=1 parity shard; lose the first data shard and
+    // reconstruct from the second data shard plus the parity shard.
+    let c2 = MsgChunk(MsgId(0), PieceNum(2, 3, 2, 2), vec![7]);
+    let parity = rs::encode_parity_shard(&[&[3u8][..], &[7u8][..]], 3, 0);
+    let c3 = MsgChunk(MsgId(0), PieceNum(3, 3, 2, 2), parity);
+
+    let mut stage = MsgStage::new(c2);
+    stage.add_chunk(c3);
+    assert!(stage.is_ready());
+    assert!(stage.merge() == Some(CompleteMessage(MsgId(0), vec![3, 7])));
+}
+
+#[test] fn merge_returns_none_instead_of_panicking_on_singular_shard_set() {
+    // Rows 0 and 256 of the Vandermonde basis alias (`256 as u8 == 0`), so a
+    // stage holding shards at those two row positions builds a singular
+    // decoding matrix. `merge` must report this as `None` instead of
+    // panicking; `Sender::enqueue` is what actually keeps this from
+    // happening in practice by refusing messages with `k + parity_shards >
+    // 256`, but `merge` shouldn't trust callers to have checked that.
+    let total = 258;
+    let k = 2;
+    let c1 = MsgChunk(MsgId(0), PieceNum(1, total, k, 2), vec![1, 2]);
+    let c2 = MsgChunk(MsgId(0), PieceNum(257, total, k, 2), vec![3, 4]);
+
+    let mut stage = MsgStage::new(c1);
+    stage.add_chunk(c2);
+    assert!(stage.is_ready());
+    assert!(stage.merge().is_none());
+}
+
+#[test] fn stream_not_ready_until_terminal_piece_arrives() {
+    let c1 = MsgChunk(MsgId(0), PieceNum(1, STREAM_CONTINUATION, 1, 0), vec![0]);
+    let stage = MsgStage::new(c1);
+    assert!(!stage.is_ready());
+}
+
+#[test] fn stream_reassembles_in_sequence_order_regardless_of_arrival_order() {
+    let c1 = MsgChunk(MsgId(0), PieceNum(1, STREAM_CONTINUATION, 1, 0), vec![0]);
+    let c2 = MsgChunk(MsgId(0), PieceNum(2, STREAM_CONTINUATION, 1, 0), vec![1]);
+    let c3 = MsgChunk(MsgId(0), PieceNum(3, 3, 1, 0), vec![2]);
+
+    let mut stage = MsgStage::new(c3);
+    stage.add_chunk(c1);
+    assert!(!stage.is_ready());
+    stage.add_chunk(c2);
+    assert!(stage.is_ready());
+    assert!(stage.merge() == Some(CompleteMessage(MsgId(0), vec![0, 1, 2])));
+}
+
 // Queue tests
 
 #[test] fn queue_single() {
-    let mut queue = MsgQueue::new(None);
-    let c1 = MsgChunk(MsgId(1), PieceNum(1, 1), vec![0]);
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    let c1 = MsgChunk(MsgId(1), PieceNum(1, 1, 1, 1), vec![0]);
 
     let res = queue.insert_chunk(c1.clone());
 
-    assert!(res.is_some());
-    assert!(res.unwrap() == CompleteMessage(MsgId(1), vec![0]));
+    assert_eq!(res, vec![CompleteMessage(MsgId(1), vec![0])]);
     assert!(queue.last_released == Some(MsgId(1)));
 
     // try to requeue the message.  It shouldn't go through this time.
     let res = queue.insert_chunk(c1);
-    assert!(res.is_none());
+    assert!(res.is_empty());
 }
 
 #[test] fn queue_double() {
-    let mut queue = MsgQueue::new(None);
-    let c1 = MsgChunk(MsgId(1), PieceNum(1, 2), vec![0]);
-    let c2 = MsgChunk(MsgId(1), PieceNum(2, 2), vec![1]);
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    let c1 = MsgChunk(MsgId(1), PieceNum(1, 2, 2, 2), vec![0]);
+    let c2 = MsgChunk(MsgId(1), PieceNum(2, 2, 2, 2), vec![1]);
 
     let res = queue.insert_chunk(c1.clone());
-    assert!(res.is_none());
+    assert!(res.is_empty());
     let res = queue.insert_chunk(c2.clone());
-    assert!(res.is_some());
-    assert!(res.unwrap() == CompleteMessage(MsgId(1), vec![0, 1]));
+    assert_eq!(res, vec![CompleteMessage(MsgId(1), vec![0, 1])]);
     assert!(queue.last_released == Some(MsgId(1)));
 
-    assert!(queue.insert_chunk(c1).is_none());
-    assert!(queue.insert_chunk(c2).is_none());
+    assert!(queue.insert_chunk(c1).is_empty());
+    assert!(queue.insert_chunk(c2).is_empty());
 }
 
 #[test] fn out_of_order() {
-    let mut queue = MsgQueue::new(None);
-    let c1 = MsgChunk(MsgId(1), PieceNum(1, 1), vec![0]);
-    let c2 = MsgChunk(MsgId(2), PieceNum(1, 1), vec![1]);
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    let c1 = MsgChunk(MsgId(1), PieceNum(1, 1, 1, 1), vec![0]);
+    let c2 = MsgChunk(MsgId(2), PieceNum(1, 1, 1, 1), vec![1]);
 
-    assert!(queue.insert_chunk(c2.clone()).is_some());
-    assert!(queue.insert_chunk(c1).is_none());
-    assert!(queue.insert_chunk(c2).is_none());
+    assert!(!queue.insert_chunk(c2.clone()).is_empty());
+    assert!(queue.insert_chunk(c1).is_empty());
+    assert!(queue.insert_chunk(c2).is_empty());
+}
+
+#[test] fn missing_shards_reports_incomplete_stages() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    let a1 = MsgChunk(MsgId(1), PieceNum(1, 2, 2, 2), vec![0]);
+    queue.insert_chunk(a1);
+
+    let missing = queue.missing_shards();
+    assert_eq!(missing.len(), 1);
+    let (id, bitmap) = missing[0].clone();
+    assert_eq!(id, MsgId(1));
+    // Shard 1 arrived, shard 2 is still missing.
+    assert_eq!(bitmap[0] & 1, 0);
+    assert_eq!(bitmap[0] & 2, 2);
+}
+
+#[test] fn missing_shards_ignores_in_progress_streams() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    let a1 = MsgChunk(MsgId(1), PieceNum(1, 2, 2, 2), vec![0]);
+    let s1 = MsgChunk(MsgId(2), PieceNum(1, STREAM_CONTINUATION, 1, 0), vec![0]);
+    queue.insert_chunk(a1);
+    queue.insert_stream_chunk(s1);
+
+    // Only the coded message's gap is reportable; the stream has no fixed
+    // shard count and would just produce a useless empty bitmap.
+    let missing = queue.missing_shards();
+    assert_eq!(missing.len(), 1);
+    assert_eq!(missing[0].0, MsgId(1));
 }
 
 #[test] fn odd_orders() {
-    let a1 = MsgChunk(MsgId(1), PieceNum(1, 2), vec![0]);
-    let a2 = MsgChunk(MsgId(1), PieceNum(2, 2), vec![1]);
+    let a1 = MsgChunk(MsgId(1), PieceNum(1, 2, 2, 2), vec![0]);
+    let a2 = MsgChunk(MsgId(1), PieceNum(2, 2, 2, 2), vec![1]);
+
+    let b1 = MsgChunk(MsgId(2), PieceNum(1, 2, 2, 2), vec![2]);
+    let b2 = MsgChunk(MsgId(2), PieceNum(2, 2, 2, 2), vec![3]);
 
-    let b1 = MsgChunk(MsgId(2), PieceNum(1, 2), vec![2]);
-    let b2 = MsgChunk(MsgId(2), PieceNum(2, 2), vec![3]);
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    assert!(queue.insert_chunk(a1.clone()).is_empty());
+    assert!(queue.insert_chunk(b1.clone()).is_empty());
+    assert!(!queue.insert_chunk(a2.clone()).is_empty());
+    assert!(!queue.insert_chunk(b2.clone()).is_empty());
 
-    let mut queue = MsgQueue::new(None);
-    assert!(queue.insert_chunk(a1.clone()).is_none());
-    assert!(queue.insert_chunk(b1.clone()).is_none());
-    assert!(queue.insert_chunk(a2.clone()).is_some());
-    assert!(queue.insert_chunk(b2.clone()).is_some());
 
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    assert!(queue.insert_chunk(a1.clone()).is_empty());
+    assert!(queue.insert_chunk(b1.clone()).is_empty());
+    assert!(!queue.insert_chunk(b2.clone()).is_empty());
+    assert!(queue.insert_chunk(a2.clone()).is_empty());
 
-    let mut queue = MsgQueue::new(None);
-    assert!(queue.insert_chunk(a1.clone()).is_none());
-    assert!(queue.insert_chunk(b1.clone()).is_none());
-    assert!(queue.insert_chunk(b2.clone()).is_some());
-    assert!(queue.insert_chunk(a2.clone()).is_none());
 
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    assert!(queue.insert_chunk(b1.clone()).is_empty());
+    assert!(!queue.insert_chunk(b2.clone()).is_empty());
+    assert!(queue.insert_chunk(a2.clone()).is_empty());
+}
+
+#[test] fn ordered_releases_in_order_after_gap_fills() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Ordered { window: 8 });
+    let c1 = MsgChunk(MsgId(0), PieceNum(1, 1, 1, 1), vec![0]);
+    let c2 = MsgChunk(MsgId(1), PieceNum(1, 1, 1, 1), vec![1]);
+    let c3 = MsgChunk(MsgId(2), PieceNum(1, 1, 1, 1), vec![2]);
+
+    // 1 and 2 arrive before 0; nothing can be released yet.
+    assert!(queue.insert_chunk(c2).is_empty());
+    assert!(queue.insert_chunk(c3).is_empty());
+
+    // 0 arriving unblocks the whole contiguous run.
+    let released = queue.insert_chunk(c1);
+    assert_eq!(released, vec![
+        CompleteMessage(MsgId(0), vec![0]),
+        CompleteMessage(MsgId(1), vec![1]),
+        CompleteMessage(MsgId(2), vec![2]),
+    ]);
+}
+
+#[test] fn ordered_advances_past_a_gap_wider_than_the_window() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Ordered { window: 2 });
+    let c1 = MsgChunk(MsgId(1), PieceNum(1, 1, 1, 1), vec![1]);
+    let c2 = MsgChunk(MsgId(2), PieceNum(1, 1, 1, 1), vec![2]);
+    let c3 = MsgChunk(MsgId(3), PieceNum(1, 1, 1, 1), vec![3]);
+
+    // Message 0 never arrives; once the buffer exceeds the window, the
+    // held messages release even though id 0 is still missing.
+    assert!(queue.insert_chunk(c1).is_empty());
+    assert!(queue.insert_chunk(c2).is_empty());
+    let released = queue.insert_chunk(c3);
+    assert_eq!(released, vec![
+        CompleteMessage(MsgId(1), vec![1]),
+        CompleteMessage(MsgId(2), vec![2]),
+        CompleteMessage(MsgId(3), vec![3]),
+    ]);
+}
+
+#[test] fn ordered_prune_spares_an_in_progress_stream() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Ordered { window: 1 });
+    // Start a stream at id 0 and leave it incomplete.
+    let s1 = MsgChunk(MsgId(0), PieceNum(1, STREAM_CONTINUATION, 1, 0), vec![0]);
+    assert!(!queue.insert_stream_chunk(s1).is_empty());
+
+    // Ordinary messages at ids 1 and 2 push the reorder buffer past its
+    // window, advancing `next_release` beyond id 0.
+    let c1 = MsgChunk(MsgId(1), PieceNum(1, 1, 1, 1), vec![1]);
+    let c2 = MsgChunk(MsgId(2), PieceNum(1, 1, 1, 1), vec![2]);
+    queue.insert_chunk(c1);
+    queue.insert_chunk(c2);
+
+    // The stream's stage would be below the new low-water mark, but it
+    // bypasses `self.mode` and must survive the prune.
+    assert!(queue.stages.contains_key(&MsgId(0)));
+
+    // It can still complete normally afterwards.
+    let s2 = MsgChunk(MsgId(0), PieceNum(2, 2, 1, 0), vec![1]);
+    assert_eq!(queue.insert_stream_chunk(s2), vec![CompleteMessage(MsgId(0), vec![1])]);
+}
+
+#[test] fn unordered_releases_immediately_and_ignores_duplicates() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Unordered { window: 8 });
+    let c1 = MsgChunk(MsgId(5), PieceNum(1, 1, 1, 1), vec![0]);
+    let c2 = MsgChunk(MsgId(1), PieceNum(1, 1, 1, 1), vec![1]);
+
+    // A later id completing first doesn't block an earlier one from
+    // still releasing afterwards.
+    assert_eq!(queue.insert_chunk(c1.clone()), vec![CompleteMessage(MsgId(5), vec![0])]);
+    assert_eq!(queue.insert_chunk(c2), vec![CompleteMessage(MsgId(1), vec![1])]);
+
+    // Resending the same message doesn't release it twice.
+    assert!(queue.insert_chunk(c1).is_empty());
+}
+
+#[test] fn unordered_forgets_duplicates_older_than_the_window() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Unordered { window: 2 });
+    let c1 = MsgChunk(MsgId(1), PieceNum(1, 1, 1, 1), vec![0]);
+    let c2 = MsgChunk(MsgId(2), PieceNum(1, 1, 1, 1), vec![1]);
+    let c3 = MsgChunk(MsgId(3), PieceNum(1, 1, 1, 1), vec![2]);
+
+    assert!(!queue.insert_chunk(c1.clone()).is_empty());
+    assert!(!queue.insert_chunk(c2).is_empty());
+    assert!(!queue.insert_chunk(c3).is_empty());
+
+    // Id 1 fell out of the window once ids 2 and 3 were released, so a
+    // duplicate of it is treated as new instead of being remembered
+    // forever.
+    assert!(!queue.insert_chunk(c1).is_empty());
+}
+
+#[test] fn stream_chunks_yield_deltas_in_order() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    let c1 = MsgChunk(MsgId(9), PieceNum(1, STREAM_CONTINUATION, 1, 0), vec![0]);
+    let c2 = MsgChunk(MsgId(9), PieceNum(2, STREAM_CONTINUATION, 1, 0), vec![1, 2]);
+    let c3 = MsgChunk(MsgId(9), PieceNum(3, 3, 1, 0), vec![3]);
+
+    // Each call only hands back what that chunk newly contributed, not
+    // the whole prefix reassembled so far.
+    assert_eq!(queue.insert_stream_chunk(c1), vec![CompleteMessage(MsgId(9), vec![0])]);
+    assert_eq!(queue.insert_stream_chunk(c2), vec![CompleteMessage(MsgId(9), vec![1, 2])]);
+    assert_eq!(queue.insert_stream_chunk(c3), vec![CompleteMessage(MsgId(9), vec![3])]);
+}
+
+#[test] fn stream_chunks_are_dropped_from_the_stage_once_emitted() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    let c1 = MsgChunk(MsgId(9), PieceNum(1, STREAM_CONTINUATION, 1, 0), vec![0, 1, 2, 3]);
+    let c2 = MsgChunk(MsgId(9), PieceNum(2, 2, 1, 0), vec![4]);
+
+    queue.insert_stream_chunk(c1);
+    // Piece 1's bytes were already delivered and should no longer be
+    // counted against the queue's resident size.
+    assert_eq!(queue.cur_size, 0);
+
+    queue.insert_stream_chunk(c2);
+    // The stream completed, so nothing of it is left resident either.
+    assert_eq!(queue.cur_size, 0);
+}
 
-    let mut queue = MsgQueue::new(None);
-    assert!(queue.insert_chunk(b1.clone()).is_none());
-    assert!(queue.insert_chunk(b2.clone()).is_some());
-    assert!(queue.insert_chunk(a2.clone()).is_none());
+#[test] fn stream_chunks_out_of_order_only_yield_once_the_gap_fills() {
+    let mut queue = MsgQueue::new(None, DeliveryMode::Monotonic);
+    let c1 = MsgChunk(MsgId(9), PieceNum(1, STREAM_CONTINUATION, 1, 0), vec![0]);
+    let c2 = MsgChunk(MsgId(9), PieceNum(2, STREAM_CONTINUATION, 1, 0), vec![1]);
+    let c3 = MsgChunk(MsgId(9), PieceNum(3, 3, 1, 0), vec![2]);
+
+    // Piece 2 arrives ahead of piece 1: nothing contiguous to release yet.
+    assert!(queue.insert_stream_chunk(c2).is_empty());
+    // The terminal piece arrives before the gap fills: still nothing.
+    assert!(queue.insert_stream_chunk(c3).is_empty());
+    // Piece 1 fills the gap, unblocking the whole stream at once.
+    assert_eq!(queue.insert_stream_chunk(c1), vec![CompleteMessage(MsgId(9), vec![0, 1, 2])]);
 }
@@ -1,33 +1,148 @@
+extern crate libc;
+
 use std::sync::mpsc;
-use std::collections::{VecDeque, HashMap};
+use std::collections::{VecDeque, HashMap, BTreeMap};
 use std::collections::hash_map::Entry;
 use std::net::{UdpSocket, ToSocketAddrs, SocketAddr};
 use std::rc::Rc;
-use std::io::Result as IoResult;
+use std::io::{self, Read, Result as IoResult};
 
 use super::msgqueue::*;
+use super::msgqueue::rs;
 use super::{UnrError, UnrResult};
 use bincode;
 
 static MSG_PADDING: u16 = 32;
 
+// `rs::systematic_matrix` evaluates row `i` of its Vandermonde basis at
+// `i as u8`, so row 256 and beyond alias an earlier row mod 256 and can
+// leave the receiver with an undecodable (singular) set of shards. Reject
+// messages that would need more shards than that up front, rather than
+// letting that happen on the wire.
+static MAX_TOTAL_SHARDS: usize = 256;
+
+// The number of recently-sent (MsgId, shard) payloads a `Sender` retains so
+// that it can service NACKs without the caller re-enqueuing the message.
+static SENT_CACHE_LIMIT: usize = 1024;
+
+/// The priority of a message waiting to be sent: lower values are sent
+/// first. Messages of equal priority are sent in round-robin fashion, one
+/// chunk at a time, so several large transfers don't starve each other.
+#[derive(Clone, Copy)]
+#[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Debug)]
+pub struct RequestPriority(pub u8);
+
+/// The packets exchanged between a `Sender` and a `Receiver`: either a data
+/// chunk, or a NACK reporting the shards a `Receiver` is still missing for
+/// a given message.
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+enum WireMsg {
+    Chunk(MsgChunk),
+    Nack(MsgId, Vec<u8>),
+    // A length-prefixed sequence of single-shard chunks bound for the same
+    // destination, coalesced into one datagram to amortize per-packet
+    // overhead across many small messages.
+    Batch(Vec<MsgChunk>)
+}
+
+// A message queued for one priority class: either fully split into chunks
+// up front, or a stream whose remaining chunks are produced lazily, one at
+// a time, so `send_one` never has to hold more than a single shard's worth
+// of a large payload in memory.
+enum OutMessage {
+    Ready(VecDeque<(MsgChunk, Rc<AddrsContainer>)>),
+    Streaming(StreamSource)
+}
+
+// Lazily turns a `Read` into a sequence of `MsgChunk`s, tagging the last
+// one with the real piece count once end-of-stream is reached (see
+// `PieceNum` and `STREAM_CONTINUATION`).
+struct StreamSource {
+    id: MsgId,
+    addrs: Rc<AddrsContainer>,
+    source: Box<Read>,
+    shard_len: usize,
+    next_seq: u16,
+    done: bool
+}
+
+impl StreamSource {
+    // Reads the next shard, looping until either `shard_len` bytes have
+    // been collected or the source reports true end-of-stream (`read`
+    // returning `Ok(0)`); a short read that isn't EOF is simply retried,
+    // since `Read` doesn't guarantee filling the buffer in one call.
+    fn fill_shard(&mut self) -> IoResult<(Vec<u8>, bool)> {
+        let mut buf = vec![0u8; self.shard_len];
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = try!(self.source.read(&mut buf[filled ..]));
+            if n == 0 { break; }
+            filled += n;
+        }
+        buf.truncate(filled);
+        let eof = filled < self.shard_len;
+        Ok((buf, eof))
+    }
+
+    // Produces the next chunk of the stream, or `None` once the terminal
+    // chunk has already been produced.
+    fn next_chunk(&mut self) -> IoResult<Option<(MsgChunk, Rc<AddrsContainer>)>> {
+        if self.done { return Ok(None); }
+
+        let (bytes, eof) = try!(self.fill_shard());
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let total = if eof { self.done = true; seq } else { STREAM_CONTINUATION };
+        let chunk = MsgChunk(self.id, PieceNum(seq, total, 1, 0), bytes);
+        Ok(Some((chunk, self.addrs.clone())))
+    }
+}
+
+/// The outcome of a `Sender::send_batch` call.
+pub struct SendBatchResult {
+    /// How many datagrams were actually handed off to the network.
+    pub sent: usize,
+    /// The chunks that failed to encode or send; these have already been
+    /// re-enqueued at their original priority, so this is purely informational.
+    pub failed: Vec<MsgChunk>
+}
+
 /// The sending end of an unreliable message socket.
 pub struct Sender {
-    out_queue: VecDeque<(MsgChunk, Rc<AddrsContainer>)>,
+    out_queue: BTreeMap<u8, VecDeque<OutMessage>>,
     last_id: u64,
     socket: UdpSocket,
     pub datagram_length: u16,
-    pub replication: u8
+    pub parity_shards: u16,
+    /// When set, `send_one` will opportunistically pack several pending
+    /// single-shard messages bound for the same destination into a single
+    /// datagram, instead of sending each as its own packet.
+    pub coalesce_small: bool,
+    sent_cache: HashMap<(u64, u16), (MsgChunk, Rc<AddrsContainer>)>,
+    sent_order: VecDeque<(u64, u16)>
 }
 
 /// The receiving end of an unreliable message socket.
 pub struct Receiver {
     socket: UdpSocket,
     queue: HashMap<SocketAddr, MsgQueue>,
-    pub datagram_length: u16
+    // Completed messages split out of a `WireMsg::Batch` that haven't been
+    // returned to the caller yet.
+    pending: VecDeque<(SocketAddr, CompleteMessage)>,
+    pub datagram_length: u16,
+    mode: DeliveryMode,
+    /// When set, a streamed message (see `Sender::enqueue_stream`) is
+    /// handed back to the caller incrementally, as each new contiguous
+    /// run of pieces arrives, instead of only once the whole
+    /// stream has completed. Each delivery is a `CompleteMessage` carrying
+    /// the stream's `MsgId` and only the bytes newly made available by
+    /// that delivery, not the whole prefix reassembled so far. Has no
+    /// effect on non-streamed messages.
+    pub incremental_streams: bool
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct AddrsContainer{
     v: Vec<SocketAddr>
 }
@@ -40,6 +155,28 @@ impl AddrsContainer {
     }
 }
 
+// A message is eligible for coalescing if it is made up of a single shard
+// that didn't need splitting or coding to begin with. Streamed messages
+// never qualify: even a one-shard-so-far stream might not be done yet.
+fn is_single_shard(message: &OutMessage) -> bool {
+    match *message {
+        OutMessage::Ready(ref chunks) => {
+            chunks.len() == 1 && {
+                let PieceNum(_, total_shards, _, _) = (chunks[0].0).1;
+                total_shards == 1
+            }
+        }
+        OutMessage::Streaming(_) => false
+    }
+}
+
+// True if `chunk` belongs to a streamed message (see `PieceNum`) rather
+// than a single verbatim shard or an RS-coded one.
+fn is_stream_chunk(chunk: &MsgChunk) -> bool {
+    let PieceNum(_, total_shards, k, _) = chunk.1;
+    k <= 1 && total_shards != 1
+}
+
 impl ToSocketAddrs for AddrsContainer {
     type Iter = ::std::vec::IntoIter<SocketAddr>;
 
@@ -53,73 +190,238 @@ impl Receiver {
     /// Constructs a receiver from a socket.
     ///
     /// `datagram_length` is the max-size of the UDP packet that you expect to
-    /// receive.
-    pub fn from_socket(socket: UdpSocket, datagram_length: u16) -> Receiver {
+    /// receive. `mode` controls the order in which completed messages from
+    /// each peer are released; see `DeliveryMode`.
+    pub fn from_socket(socket: UdpSocket, datagram_length: u16, mode: DeliveryMode) -> Receiver {
         Receiver {
             socket: socket,
             datagram_length: datagram_length,
-            queue: HashMap::new()
+            queue: HashMap::new(),
+            pending: VecDeque::new(),
+            mode: mode,
+            incremental_streams: false
         }
     }
 
     /// Blocks until a completed message is received, and returns the Socket
     /// Address that the message came from.
+    ///
+    /// A single incoming datagram can be a `WireMsg::Batch` coalescing
+    /// several single-shard messages, and a single chunk can itself unblock
+    /// several buffered messages under `DeliveryMode::Ordered`; when that
+    /// happens, any messages completed beyond the first are buffered and
+    /// returned by the next calls to `poll` without touching the socket
+    /// again.
     pub fn poll(&mut self) -> UnrResult<(SocketAddr, CompleteMessage)> {
+        if let Some(next) = self.pending.pop_front() {
+            return Ok(next);
+        }
+
         let mut buf: Vec<u8> = (0 .. self.datagram_length).map(|_| 0).collect();
         loop {
             let (amnt, from) = try!(self.socket.recv_from(&mut buf[..]));
             let data = &buf[0 .. amnt];
-            let chunk: MsgChunk = try!(bincode::decode(data));
+            let wire: WireMsg = try!(bincode::decode(data));
+            let mode = self.mode;
+            let incremental = self.incremental_streams;
 
-            let q = self.queue.entry(from.clone()).or_insert_with(|| MsgQueue::new());
-            if let Some(completed) = q.insert_chunk(chunk) {
-                return Ok((from, completed));
+            match wire {
+                WireMsg::Chunk(chunk) => {
+                    let q = self.queue.entry(from.clone()).or_insert_with(|| MsgQueue::new(None, mode));
+                    let completed = if incremental && is_stream_chunk(&chunk) {
+                        q.insert_stream_chunk(chunk)
+                    } else {
+                        q.insert_chunk(chunk)
+                    };
+                    for completed in completed {
+                        self.pending.push_back((from.clone(), completed));
+                    }
+                    if let Some(next) = self.pending.pop_front() {
+                        return Ok(next);
+                    }
+                }
+                WireMsg::Batch(chunks) => {
+                    let q = self.queue.entry(from.clone()).or_insert_with(|| MsgQueue::new(None, mode));
+                    for chunk in chunks {
+                        let completed = if incremental && is_stream_chunk(&chunk) {
+                            q.insert_stream_chunk(chunk)
+                        } else {
+                            q.insert_chunk(chunk)
+                        };
+                        for completed in completed {
+                            self.pending.push_back((from.clone(), completed));
+                        }
+                    }
+                    if let Some(next) = self.pending.pop_front() {
+                        return Ok(next);
+                    }
+                }
+                // A Receiver only ever emits NACKs, never expects one.
+                WireMsg::Nack(..) => continue,
             }
         }
     }
+
+    /// Sends a NACK back to every peer with a message still being
+    /// reassembled, reporting exactly the shards that haven't arrived yet,
+    /// so their `Sender` can retransmit just those shards.
+    pub fn send_nacks(&mut self) -> UnrResult<()> {
+        let bound = bincode::SizeLimit::Bounded(self.datagram_length as u64);
+        for (addr, queue) in self.queue.iter() {
+            for (id, bitmap) in queue.missing_shards() {
+                let bytes = try!(bincode::encode(&WireMsg::Nack(id, bitmap), bound));
+                try!(self.socket.send_to(&bytes[..], addr));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Sender {
     /// Constructs a sender from a socket.
     ///
     /// * `datagram_length` is the max-size of a UDP packet.
-    /// * `replication` is the amout of times that a chunk will get re-sent.
+    /// * `parity_shards` is the number of Reed-Solomon parity shards `m`
+    ///   computed for every multi-chunk message, in addition to its `k`
+    ///   data shards. The receiver can reconstruct the message from any
+    ///   `k` of the `k + m` shards, so losing up to `parity_shards` of them
+    ///   in transit is survivable.
     ///
-    /// `replication` should almost always be `1`, and rarely `2` or above.
-    pub fn from_socket(socket: UdpSocket, datagram_length: u16, replication: u8) -> Sender {
+    /// `parity_shards` should almost always be `1` or `2`; each one adds a
+    /// full extra shard of bandwidth to every multi-chunk message.
+    pub fn from_socket(socket: UdpSocket, datagram_length: u16, parity_shards: u16) -> Sender {
         Sender {
-            out_queue: VecDeque::new(),
+            out_queue: BTreeMap::new(),
             last_id: 0,
             socket: socket,
             datagram_length: datagram_length,
-            replication: replication
+            parity_shards: parity_shards,
+            coalesce_small: false,
+            sent_cache: HashMap::new(),
+            sent_order: VecDeque::new()
         }
     }
 
-    /// Adds a message to the queue of chunks to send out.
-    pub fn enqueue<T: ToSocketAddrs>(&mut self, message: Vec<u8>, addrs: T) -> UnrResult<()> {
+    // Queues a single already-built chunk for (re)transmission at `priority`.
+    fn requeue(&mut self, chunk: MsgChunk, addrs: Rc<AddrsContainer>, priority: RequestPriority) {
+        let mut chunks = VecDeque::new();
+        chunks.push_back((chunk, addrs));
+        self.out_queue.entry(priority.0).or_insert_with(VecDeque::new)
+            .push_back(OutMessage::Ready(chunks));
+    }
+
+    /// Adds a message to the queue of chunks to send out at the given
+    /// `priority`.
+    ///
+    /// Messages that fit into a single datagram are sent as-is. Larger
+    /// messages are split into `k` data shards plus `self.parity_shards`
+    /// Reed-Solomon parity shards, so the receiver can reassemble the
+    /// message from any `k` of the `k + m` shards that arrive.
+    ///
+    /// Returns an error without enqueueing anything if `k + parity_shards`
+    /// would exceed `MAX_TOTAL_SHARDS`: the coding matrix can't tell that
+    /// many shards apart (see `MAX_TOTAL_SHARDS`).
+    pub fn enqueue<T: ToSocketAddrs>(&mut self, message: Vec<u8>, addrs: T, priority: RequestPriority) -> UnrResult<()> {
         self.last_id += 1;
         let id = self.last_id;
         let addrs = Rc::new(try!(AddrsContainer::from_to_sock(addrs)));
-        let num_chunks = message.len() / ((self.datagram_length - MSG_PADDING) as usize);
+        let orig_len = message.len() as u32;
+        let shard_len = (self.datagram_length - MSG_PADDING) as usize;
+        let k = if message.len() <= shard_len { 1 } else {
+            (message.len() + shard_len - 1) / shard_len
+        };
+
+        if k + self.parity_shards as usize > MAX_TOTAL_SHARDS {
+            return Err(From::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message needs more than 256 total shards (k + parity_shards); split it into smaller messages or lower parity_shards"
+            )));
+        }
+
+        let mut chunks = VecDeque::new();
+
+        if k == 1 {
+            let chunk = MsgChunk(MsgId(id), PieceNum(1, 1, 1, orig_len), message);
+            chunks.push_back((chunk, addrs));
+        } else {
+            let m = self.parity_shards as usize;
+            let total = k + m;
+
+            let mut data_shards: Vec<Vec<u8>> = message[..].chunks(shard_len)
+                .map(|c| { let mut v = Vec::new(); v.push_all(c); v })
+                .collect();
+            // The last data shard must be zero-padded to a fixed shard
+            // length before coding; the original length is carried in
+            // `PieceNum` so the receiver can truncate the padding back off
+            // on reassembly.
+            if let Some(last) = data_shards.last_mut() {
+                while last.len() < shard_len { last.push(0); }
+            }
+
+            for (i, shard) in data_shards.iter().enumerate() {
+                let chunk = MsgChunk(
+                    MsgId(id), PieceNum((i + 1) as u16, total as u16, k as u16, orig_len), shard.clone());
+                chunks.push_back((chunk, addrs.clone()));
+            }
 
-        for _ in 0 .. self.replication {
-            let mut chunk_count = 0;
-            for chunk in message[..].chunks((self.datagram_length - MSG_PADDING) as usize) {
-                let mut v = Vec::new();
-                v.push_all(chunk);
+            let refs: Vec<&[u8]> = data_shards.iter().map(|s| &s[..]).collect();
+            for p in 0 .. m {
+                let parity = rs::encode_parity_shard(&refs[..], total, p);
                 let chunk = MsgChunk(
-                    MsgId(id), PieceNum(chunk_count + 1, (num_chunks + 1) as u16), v);
-                self.out_queue.push_back((chunk, addrs.clone()));
-                chunk_count += 1;
+                    MsgId(id), PieceNum((k + p + 1) as u16, total as u16, k as u16, orig_len), parity);
+                chunks.push_back((chunk, addrs.clone()));
             }
         }
 
+        self.out_queue.entry(priority.0).or_insert_with(VecDeque::new)
+            .push_back(OutMessage::Ready(chunks));
+
+        Ok(())
+    }
+
+    /// Adds a streamed message to the queue of chunks to send out at the
+    /// given `priority`.
+    ///
+    /// Unlike `enqueue`, the full payload is never materialized: `source`
+    /// is read lazily, one shard at a time, as `send_one` asks for the
+    /// next chunk to send. This bounds memory use to a single shard
+    /// regardless of how large the eventual message turns out to be, at
+    /// the cost of forgoing Reed-Solomon parity, since coding requires the
+    /// full set of data shards up front. The terminal chunk is tagged with
+    /// the real piece count once `source` is exhausted; see `PieceNum`.
+    pub fn enqueue_stream<T, R>(&mut self, source: R, addrs: T, priority: RequestPriority) -> UnrResult<()>
+        where T: ToSocketAddrs, R: Read + 'static
+    {
+        self.last_id += 1;
+        let id = self.last_id;
+        let addrs = Rc::new(try!(AddrsContainer::from_to_sock(addrs)));
+        let shard_len = (self.datagram_length - MSG_PADDING) as usize;
+
+        let stream = StreamSource {
+            id: MsgId(id),
+            addrs: addrs,
+            source: Box::new(source),
+            shard_len: shard_len,
+            next_seq: 1,
+            done: false
+        };
+
+        self.out_queue.entry(priority.0).or_insert_with(VecDeque::new)
+            .push_back(OutMessage::Streaming(stream));
+
         Ok(())
     }
 
     /// Attempts to send one UDP packet over the network.
     ///
+    /// The highest-priority non-empty class is chosen, and one chunk is
+    /// sent from the message at the front of that class's queue; if the
+    /// message still has chunks left, it's moved to the back of the class
+    /// so other messages of the same priority get a turn. This lets
+    /// several equal-priority transfers interleave chunk-by-chunk instead
+    /// of serializing, while a higher-priority message always preempts
+    /// lower-priority ones.
+    ///
     /// The size of the UDP packet is bounded by `self.datagram_length`.
     ///
     /// ## Returns
@@ -128,17 +430,542 @@ impl Sender {
     /// * Ok(false) if theere are no more messages in the queue.
     pub fn send_one(&mut self) -> UnrResult<bool> {
         let bound = bincode::SizeLimit::Bounded(self.datagram_length as u64);
-        if let Some((next, addrs)) = self.out_queue.pop_front() {
-            let bytes = try!(bincode::encode(&next, bound));
+
+        if self.coalesce_small {
+            if let Some(&priority) = self.out_queue.keys().next() {
+                if let Some((bytes, addrs)) = self.build_batch(priority, bound) {
+                    try!(self.socket.send_to(&bytes[..], &*addrs));
+                    if self.out_queue.get(&priority).map_or(false, |c| c.is_empty()) {
+                        self.out_queue.remove(&priority);
+                    }
+                    return Ok(!self.out_queue.is_empty());
+                }
+            }
+        }
+
+        if let Some((_priority, chunk, addrs)) = try!(self.pop_one_chunk()) {
+            let bytes = try!(bincode::encode(&WireMsg::Chunk(chunk.clone()), bound));
             try!(self.socket.send_to(&bytes[..], &*addrs));
+            self.remember_sent(chunk, addrs);
         }
 
         Ok(!self.out_queue.is_empty())
     }
 
+    // Pops the next single chunk to send, from the highest-priority
+    // non-empty class, round-robining through the messages in that class.
+    // Shared by `send_one` and `send_batch`.
+    fn pop_one_chunk(&mut self) -> UnrResult<Option<(u8, MsgChunk, Rc<AddrsContainer>)>> {
+        let priority = match self.out_queue.keys().next() {
+            Some(&p) => p,
+            None => return Ok(None),
+        };
+
+        let mut message = self.out_queue.get_mut(&priority).unwrap().pop_front().unwrap();
+
+        // `Streaming`'s read can fail transiently (interrupted, would-block,
+        // ...); if it does, put `message` back before propagating the
+        // error instead of dropping the rest of the stream on the floor.
+        let next = match message {
+            OutMessage::Ready(ref mut chunks) => Ok(chunks.pop_front()),
+            OutMessage::Streaming(ref mut stream) => stream.next_chunk(),
+        };
+        let popped = match next {
+            Ok(popped) => popped,
+            Err(e) => {
+                self.out_queue.get_mut(&priority).unwrap().push_front(message);
+                return Err(From::from(e));
+            }
+        };
+
+        let still_pending = match message {
+            OutMessage::Ready(ref chunks) => !chunks.is_empty(),
+            OutMessage::Streaming(ref stream) => !stream.done,
+        };
+
+        let class = self.out_queue.get_mut(&priority).unwrap();
+        if still_pending {
+            class.push_back(message);
+        }
+        if class.is_empty() {
+            self.out_queue.remove(&priority);
+        }
+
+        Ok(popped.map(|(chunk, addrs)| (priority, chunk, addrs)))
+    }
+
+    /// Collects up to `max` ready `(bytes, addr)` pairs and submits them in
+    /// a single `sendmmsg(2)` call on platforms that support it (falling
+    /// back to a loop of `send_to` elsewhere). A failure sending one
+    /// packet does not abort the rest of the batch: every chunk that
+    /// failed to send (or to even encode) is re-enqueued at its original
+    /// priority and also reported in the returned `SendBatchResult`, so the
+    /// caller can retry just those instead of losing the whole batch.
+    pub fn send_batch(&mut self, max: usize) -> UnrResult<SendBatchResult> {
+        let bound = bincode::SizeLimit::Bounded(self.datagram_length as u64);
+        let mut ready: Vec<(u8, MsgChunk, Rc<AddrsContainer>, Vec<u8>)> = Vec::new();
+        let mut result = SendBatchResult { sent: 0, failed: Vec::new() };
+
+        for _ in 0 .. max {
+            let (priority, chunk, addrs) = match self.pop_one_chunk() {
+                Ok(Some(item)) => item,
+                Ok(None) => break,
+                Err(e) => {
+                    // Put back what was already pulled off the queue before
+                    // propagating the error, so none of it is lost.
+                    for (priority, chunk, addrs, _) in ready {
+                        self.requeue(chunk, addrs, RequestPriority(priority));
+                    }
+                    return Err(e);
+                }
+            };
+
+            match bincode::encode(&WireMsg::Chunk(chunk.clone()), bound) {
+                Ok(bytes) => ready.push((priority, chunk, addrs, bytes)),
+                Err(_) => {
+                    self.requeue(chunk.clone(), addrs, RequestPriority(priority));
+                    result.failed.push(chunk);
+                }
+            }
+        }
+
+        let targets: Vec<(Vec<u8>, SocketAddr)> = ready.iter()
+            .map(|&(_, _, ref addrs, ref bytes)| (bytes.clone(), addrs.v[0]))
+            .collect();
+        let outcomes = sendmmsg::send_batch(&self.socket, &targets[..]);
+
+        for ((priority, chunk, addrs, _bytes), ok) in ready.into_iter().zip(outcomes) {
+            if ok {
+                result.sent += 1;
+                self.remember_sent(chunk, addrs);
+            } else {
+                self.requeue(chunk.clone(), addrs, RequestPriority(priority));
+                result.failed.push(chunk);
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Pulls every pending single-shard message in `priority`'s queue that
+    // is bound for the same destination as the first one found, and packs
+    // as many of them as fit into one datagram into a `WireMsg::Batch`.
+    // Anything that doesn't match, or doesn't fit, is put back unchanged.
+    // Returns `None` if fewer than two messages could be batched together;
+    // nothing is sent or dropped then, but a message it pulled ends up
+    // behind the ones it skipped.
+    fn build_batch(&mut self, priority: u8, bound: bincode::SizeLimit) -> Option<(Vec<u8>, Rc<AddrsContainer>)> {
+        let (batch, addrs) = {
+            let class = self.out_queue.get_mut(&priority).unwrap();
+
+            let anchor_addrs = match class.iter().find(|m| is_single_shard(m)) {
+                Some(&OutMessage::Ready(ref chunks)) => chunks[0].1.clone(),
+                Some(&OutMessage::Streaming(_)) => unreachable!("is_single_shard excludes Streaming"),
+                None => return None,
+            };
+
+            let mut kept = VecDeque::new();
+            let mut batch: Vec<MsgChunk> = Vec::new();
+
+            while let Some(message) = class.pop_front() {
+                let single = is_single_shard(&message) && match message {
+                    OutMessage::Ready(ref chunks) => chunks[0].1 == anchor_addrs,
+                    OutMessage::Streaming(_) => false,
+                };
+                if !single {
+                    kept.push_back(message);
+                    continue;
+                }
+
+                let chunk = match message {
+                    OutMessage::Ready(chunks) => chunks.into_iter().next().unwrap().0,
+                    OutMessage::Streaming(_) => unreachable!("checked single above"),
+                };
+                let mut trial = batch.clone();
+                trial.push(chunk.clone());
+
+                if bincode::encode(&WireMsg::Batch(trial), bound).is_ok() {
+                    batch.push(chunk);
+                } else {
+                    let mut chunks = VecDeque::new();
+                    chunks.push_back((chunk, anchor_addrs.clone()));
+                    kept.push_back(OutMessage::Ready(chunks));
+                }
+            }
+
+            for message in kept {
+                class.push_back(message);
+            }
+
+            if batch.len() < 2 {
+                for chunk in batch {
+                    let mut chunks = VecDeque::new();
+                    chunks.push_back((chunk, anchor_addrs.clone()));
+                    class.push_back(OutMessage::Ready(chunks));
+                }
+                return None;
+            }
+
+            (batch, anchor_addrs)
+        };
+
+        let bytes = match bincode::encode(&WireMsg::Batch(batch.clone()), bound) {
+            Ok(bytes) => bytes,
+            Err(_) => return None,
+        };
+
+        for chunk in batch {
+            self.remember_sent(chunk, addrs.clone());
+        }
+
+        Some((bytes, addrs))
+    }
+
     /// Attemts to send all UDP packets by repeatedly calling `send_one`.
     pub fn send_all(&mut self) -> UnrResult<()> {
         while try!(self.send_one()) {}
         Ok(())
     }
+
+    // Retains a just-sent chunk so it can be retransmitted on a NACK,
+    // evicting the oldest entry once `SENT_CACHE_LIMIT` is exceeded.
+    fn remember_sent(&mut self, chunk: MsgChunk, addrs: Rc<AddrsContainer>) {
+        let MsgId(id) = chunk.0;
+        let PieceNum(shard, _, _, _) = chunk.1;
+        let key = (id, shard);
+
+        self.sent_cache.insert(key, (chunk, addrs));
+        self.sent_order.push_back(key);
+        if self.sent_order.len() > SENT_CACHE_LIMIT {
+            if let Some(old_key) = self.sent_order.pop_front() {
+                self.sent_cache.remove(&old_key);
+            }
+        }
+    }
+
+    /// Consumes a NACK for `msg_id` reporting `bitmap` (one bit per shard,
+    /// set if missing) and re-enqueues exactly the named shards, pulled
+    /// from the recently-sent cache, at `RequestPriority(0)` so repairs are
+    /// sent ahead of new traffic.
+    pub fn handle_nack(&mut self, msg_id: MsgId, bitmap: &[u8]) {
+        let MsgId(id) = msg_id;
+        for i in 0 .. bitmap.len() * 8 {
+            if bitmap[i / 8] & (1 << (i % 8)) == 0 { continue; }
+            let shard = (i + 1) as u16;
+
+            if let Some((chunk, addrs)) = self.sent_cache.get(&(id, shard)).cloned() {
+                self.requeue(chunk, addrs, RequestPriority(0));
+            }
+        }
+    }
+
+    /// Blocks until a NACK arrives on this sender's socket and hands it to
+    /// `handle_nack`. Any non-NACK datagram received here is ignored.
+    pub fn poll_nack(&mut self) -> UnrResult<()> {
+        let mut buf: Vec<u8> = (0 .. self.datagram_length).map(|_| 0).collect();
+        let (amnt, _) = try!(self.socket.recv_from(&mut buf[..]));
+        let data = &buf[0 .. amnt];
+        let wire: WireMsg = try!(bincode::decode(data));
+
+        if let WireMsg::Nack(msg_id, bitmap) = wire {
+            self.handle_nack(msg_id, &bitmap[..]);
+        }
+
+        Ok(())
+    }
+}
+
+// Submits a batch of already-encoded datagrams with as few syscalls as
+// possible. Returns one bool per input packet, in order, reporting whether
+// that specific packet was handed off successfully.
+#[cfg(target_os = "linux")]
+mod sendmmsg {
+    use std::mem;
+    use std::ptr;
+    use std::net::{UdpSocket, SocketAddr};
+    use std::os::unix::io::AsRawFd;
+    use super::libc;
+
+    pub fn send_batch(socket: &UdpSocket, packets: &[(Vec<u8>, SocketAddr)]) -> Vec<bool> {
+        if packets.is_empty() { return Vec::new(); }
+
+        let fd = socket.as_raw_fd();
+        let addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+            packets.iter().map(|&(_, addr)| to_sockaddr(addr)).collect();
+
+        let mut iovecs: Vec<libc::iovec> = packets.iter().map(|&(ref bytes, _)| {
+            libc::iovec {
+                iov_base: bytes.as_ptr() as *mut _,
+                iov_len: bytes.len() as libc::size_t
+            }
+        }).collect();
+
+        let mut msgs: Vec<libc::mmsghdr> = (0 .. packets.len()).map(|i| {
+            libc::mmsghdr {
+                msg_hdr: libc::msghdr {
+                    msg_name: &addrs[i].0 as *const _ as *mut libc::c_void,
+                    msg_namelen: addrs[i].1,
+                    msg_iov: &mut iovecs[i] as *mut libc::iovec,
+                    msg_iovlen: 1,
+                    msg_control: ptr::null_mut(),
+                    msg_controllen: 0,
+                    msg_flags: 0
+                },
+                msg_len: 0
+            }
+        }).collect();
+
+        // `sendmmsg` stops at the first packet it can't send; mark that one
+        // failed and resubmit the rest, so one failure doesn't abort them.
+        let mut outcomes = Vec::with_capacity(packets.len());
+        let mut start = 0;
+        while start < msgs.len() {
+            let sent = unsafe {
+                libc::sendmmsg(fd, msgs[start ..].as_mut_ptr(), (msgs.len() - start) as u32, 0)
+            };
+            let sent = if sent < 0 { 0 } else { sent as usize };
+            for _ in 0 .. sent { outcomes.push(true); }
+            start += sent;
+            if start < msgs.len() {
+                outcomes.push(false);
+                start += 1;
+            }
+        }
+        outcomes
+    }
+
+    fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        unsafe {
+            let mut storage: libc::sockaddr_storage = mem::zeroed();
+            let len = match addr {
+                SocketAddr::V4(a) => {
+                    let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                    sin.sin_port = a.port().to_be();
+                    sin.sin_addr = libc::in_addr { s_addr: u32::from(*a.ip()).to_be() };
+                    mem::size_of::<libc::sockaddr_in>()
+                }
+                SocketAddr::V6(a) => {
+                    let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    sin6.sin6_port = a.port().to_be();
+                    sin6.sin6_addr = libc::in6_addr { s6_addr: a.ip().octets() };
+                    sin6.sin6_flowinfo = a.flowinfo();
+                    sin6.sin6_scope_id = a.scope_id();
+                    mem::size_of::<libc::sockaddr_in6>()
+                }
+            };
+            (storage, len as libc::socklen_t)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sendmmsg {
+    use std::net::{UdpSocket, SocketAddr};
+
+    pub fn send_batch(socket: &UdpSocket, packets: &[(Vec<u8>, SocketAddr)]) -> Vec<bool> {
+        packets.iter()
+            .map(|&(ref bytes, addr)| socket.send_to(&bytes[..], addr).is_ok())
+            .collect()
+    }
+}
+
+// Loopback socket bound to an OS-assigned port, just so a `Sender` has
+// somewhere to send to; these tests only care about queue bookkeeping, not
+// what actually crosses the wire.
+fn test_socket() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").unwrap()
+}
+
+// Sender scheduling tests
+
+#[test] fn round_robin_interleaves_chunks_of_equal_priority_messages() {
+    // shard_len = 40 - 32 = 8, so a 20-byte message splits into 3 data
+    // shards (k == 3, m == 0).
+    let mut sender = Sender::from_socket(test_socket(), 40, 0);
+    sender.enqueue(vec![1; 20], "127.0.0.1:9", RequestPriority(0)).unwrap();
+    sender.enqueue(vec![2; 20], "127.0.0.1:9", RequestPriority(0)).unwrap();
+
+    // Equal-priority messages should interleave chunk-by-chunk rather than
+    // one message draining fully before the other gets a turn.
+    let mut ids = Vec::new();
+    for _ in 0 .. 6 {
+        let (_, chunk, _) = sender.pop_one_chunk().unwrap().unwrap();
+        let MsgId(id) = chunk.0;
+        ids.push(id);
+    }
+    assert_eq!(ids, vec![1, 2, 1, 2, 1, 2]);
+}
+
+#[test] fn higher_priority_class_is_drained_before_a_lower_one() {
+    let mut sender = Sender::from_socket(test_socket(), 128, 0);
+    sender.enqueue(vec![9], "127.0.0.1:9", RequestPriority(5)).unwrap();
+    sender.enqueue(vec![1], "127.0.0.1:9", RequestPriority(0)).unwrap();
+
+    let (priority, chunk, _) = sender.pop_one_chunk().unwrap().unwrap();
+    assert_eq!(priority, 0);
+    assert_eq!(chunk.2, vec![1]);
+}
+
+// NACK handling tests
+
+#[test] fn handle_nack_requeues_only_the_missing_shards_at_priority_zero() {
+    // shard_len = 40 - 32 = 8, so a 20-byte message splits into 3 data
+    // shards (k == 3, m == 0).
+    let mut sender = Sender::from_socket(test_socket(), 40, 0);
+    sender.enqueue(vec![1; 20], "127.0.0.1:9", RequestPriority(5)).unwrap();
+
+    // Drain and "send" all 3 shards so they land in the sent-shard cache.
+    for _ in 0 .. 3 {
+        let (_, chunk, addrs) = sender.pop_one_chunk().unwrap().unwrap();
+        sender.remember_sent(chunk, addrs);
+    }
+    assert!(sender.pop_one_chunk().unwrap().is_none());
+
+    // Report only shard 2 missing (bit 1 set); shards 1 and 3 arrived fine.
+    sender.handle_nack(MsgId(1), &[0b0000_0010]);
+
+    let (priority, chunk, _) = sender.pop_one_chunk().unwrap().unwrap();
+    assert_eq!(priority, 0);
+    let PieceNum(shard, _, _, _) = chunk.1;
+    assert_eq!(shard, 2);
+
+    // Nothing else was re-enqueued.
+    assert!(sender.pop_one_chunk().unwrap().is_none());
+}
+
+#[test] fn handle_nack_ignores_shards_outside_the_sent_cache() {
+    let mut sender = Sender::from_socket(test_socket(), 40, 0);
+
+    // No message with this id was ever sent, so there's nothing in the
+    // sent-shard cache to retransmit; the NACK is simply a no-op.
+    sender.handle_nack(MsgId(7), &[0b1111_1111]);
+    assert!(sender.pop_one_chunk().unwrap().is_none());
+}
+
+// Datagram coalescing tests
+
+#[test] fn coalesce_small_packs_same_destination_messages_into_one_send() {
+    let mut sender = Sender::from_socket(test_socket(), 512, 0);
+    sender.coalesce_small = true;
+    sender.enqueue(vec![1], "127.0.0.1:9", RequestPriority(0)).unwrap();
+    sender.enqueue(vec![2], "127.0.0.1:9", RequestPriority(0)).unwrap();
+    sender.enqueue(vec![3], "127.0.0.1:9", RequestPriority(0)).unwrap();
+
+    // All three are single-shard and bound for the same destination, so
+    // one `send_one` call should drain the whole queue via `build_batch`
+    // instead of needing three separate sends.
+    assert!(!sender.send_one().unwrap());
+    assert!(sender.out_queue.is_empty());
+    assert_eq!(sender.sent_cache.len(), 3);
+}
+
+#[test] fn coalesce_small_does_not_batch_a_lone_message() {
+    let mut sender = Sender::from_socket(test_socket(), 512, 0);
+    sender.coalesce_small = true;
+    sender.enqueue(vec![1], "127.0.0.1:9", RequestPriority(0)).unwrap();
+
+    // Nothing to batch a single message with: `build_batch` declines and
+    // `send_one` falls back to sending it as a plain chunk.
+    assert!(!sender.send_one().unwrap());
+    assert_eq!(sender.sent_cache.len(), 1);
+}
+
+#[test] fn coalesce_small_does_not_mix_different_destinations() {
+    let mut sender = Sender::from_socket(test_socket(), 512, 0);
+    sender.coalesce_small = true;
+    sender.enqueue(vec![1], "127.0.0.1:9001", RequestPriority(0)).unwrap();
+    sender.enqueue(vec![2], "127.0.0.1:9002", RequestPriority(0)).unwrap();
+
+    // Two different destinations can't share one datagram, so each has to
+    // go out as its own send.
+    assert!(sender.send_one().unwrap());
+    assert!(!sender.send_one().unwrap());
+    assert_eq!(sender.sent_cache.len(), 2);
+}
+
+// Batched sending tests
+
+#[test] fn send_batch_reports_how_many_packets_were_sent() {
+    let mut sender = Sender::from_socket(test_socket(), 512, 0);
+    sender.enqueue(vec![1], "127.0.0.1:9", RequestPriority(0)).unwrap();
+    sender.enqueue(vec![2], "127.0.0.1:9", RequestPriority(1)).unwrap();
+
+    let result = sender.send_batch(10).unwrap();
+    assert_eq!(result.sent, 2);
+    assert!(result.failed.is_empty());
+    assert!(sender.out_queue.is_empty());
+    assert_eq!(sender.sent_cache.len(), 2);
+}
+
+#[test] fn send_batch_respects_the_max_argument() {
+    let mut sender = Sender::from_socket(test_socket(), 512, 0);
+    sender.enqueue(vec![1], "127.0.0.1:9", RequestPriority(0)).unwrap();
+    sender.enqueue(vec![2], "127.0.0.1:9", RequestPriority(0)).unwrap();
+
+    // Only one chunk requested; the second message is left queued for a
+    // later call instead of being drained along with it.
+    let result = sender.send_batch(1).unwrap();
+    assert_eq!(result.sent, 1);
+    assert_eq!(sender.sent_cache.len(), 1);
+    assert!(!sender.out_queue.is_empty());
+}
+
+// Streamed message tests
+
+// A `Read` that fails on its first call and succeeds afterwards, to
+// exercise how `pop_one_chunk` handles a transient error partway through
+// a stream.
+struct FlakyReader {
+    calls: u32
+}
+
+impl Read for FlakyReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.calls += 1;
+        if self.calls == 1 {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "simulated transient failure"));
+        }
+        for b in buf.iter_mut() { *b = 9; }
+        Ok(buf.len())
+    }
+}
+
+#[test] fn stream_chunks_carry_continuation_until_the_source_is_exhausted() {
+    use std::io::Cursor;
+
+    // shard_len = 40 - 32 = 8; 20 bytes needs 3 pieces, the last one short.
+    let mut sender = Sender::from_socket(test_socket(), 40, 0);
+    sender.enqueue_stream(Cursor::new(vec![1u8; 20]), "127.0.0.1:9", RequestPriority(0)).unwrap();
+
+    let mut seqs = Vec::new();
+    let mut totals = Vec::new();
+    while let Some((_, chunk, _)) = sender.pop_one_chunk().unwrap() {
+        let PieceNum(seq, total, k, _) = chunk.1;
+        assert_eq!(k, 1);
+        seqs.push(seq);
+        totals.push(total);
+    }
+
+    assert_eq!(seqs, vec![1, 2, 3]);
+    // Every piece but the last carries the continuation sentinel; only the
+    // terminal piece, once EOF is known, carries the real piece count.
+    assert_eq!(totals, vec![STREAM_CONTINUATION, STREAM_CONTINUATION, 3]);
+}
+
+#[test] fn pop_one_chunk_requeues_the_stream_on_a_transient_read_error() {
+    let mut sender = Sender::from_socket(test_socket(), 40, 0);
+    sender.enqueue_stream(FlakyReader { calls: 0 }, "127.0.0.1:9", RequestPriority(0)).unwrap();
+
+    // The first read fails transiently; the error propagates but the
+    // stream must still be sitting in the queue afterwards, not dropped.
+    assert!(sender.pop_one_chunk().is_err());
+    assert!(!sender.out_queue.is_empty());
+
+    // A retry picks the same stream back up from the start and succeeds.
+    let (_, chunk, _) = sender.pop_one_chunk().unwrap().unwrap();
+    let PieceNum(seq, _, k, _) = chunk.1;
+    assert_eq!(seq, 1);
+    assert_eq!(k, 1);
 }
\ No newline at end of file